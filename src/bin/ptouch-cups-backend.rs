@@ -0,0 +1,280 @@
+//! CUPS raster backend for `rust-ptouch`
+//!
+//! Decodes CUPS raster pages from stdin and feeds them through the
+//! existing [`PTouch::print_raw`] path, so the driver can be dropped into
+//! a CUPS `usb://` backend slot and print from ordinary applications
+//! rather than only via the Rust API.
+//!
+//! See the "Writing a CUPS Backend" and raster format specs for the
+//! argument/status-line conventions implemented here.
+
+use std::convert::TryInto;
+use std::env;
+use std::io::{self, Read};
+use std::process::exit;
+
+use ptouch::device::DeviceStatus;
+use ptouch::{Filter, PTouch, PrintInfo};
+
+/// Raster line width `PTouch::print_raw` expects, 128 dots (16 bytes, 1bpp)
+const LINE_BYTES: usize = 16;
+const LINE_DOTS: usize = LINE_BYTES * 8;
+
+/// Size (in bytes) of a `cups_page_header2_t`, see `cups/raster.h`
+const PAGE_HEADER_LEN: usize = 1796;
+
+const SYNC_V2: [u8; 4] = *b"RaS2";
+
+/// Fields of interest from a CUPS raster page header, the remainder of the
+/// (fixed-size) struct is skipped rather than modelled in full
+struct PageHeader {
+    width: u32,
+    height: u32,
+    bits_per_pixel: u32,
+    bytes_per_line: u32,
+}
+
+impl PageHeader {
+    /// Offsets below match `cups_page_header2_t` (4 x 64 byte media strings,
+    /// then 29 `unsigned` fields, before the cups-specific block begins)
+    fn parse(buff: &[u8; PAGE_HEADER_LEN]) -> Self {
+        let u32_at = |offset: usize| -> u32 { u32::from_ne_bytes(buff[offset..offset + 4].try_into().unwrap()) };
+
+        Self {
+            width: u32_at(372),
+            height: u32_at(376),
+            bits_per_pixel: u32_at(388),
+            bytes_per_line: u32_at(392),
+        }
+    }
+}
+
+fn emit_state(msg: &str) {
+    eprintln!("STATE: {}", msg);
+}
+
+fn emit_info(msg: &str) {
+    eprintln!("INFO: {}", msg);
+}
+
+/// Translate a polled [`ptouch::device::Status`] into backend status lines
+fn emit_status(status: &ptouch::device::Status) {
+    if !status.error1.is_empty() || !status.error2.is_empty() {
+        // `printer-state-reasons` keywords are comma separated, each its own
+        // `foo-error`/`foo-warning` token, not a Rust Debug dump
+        let reasons: Vec<String> = status
+            .error1
+            .iter()
+            .chain(status.error2.iter())
+            .map(|e| format!("{:?}-error", e).to_lowercase())
+            .collect();
+
+        emit_state(&format!("+{}", reasons.join(",")));
+        return;
+    }
+
+    match status.status_type {
+        DeviceStatus::PhaseChange => emit_info("Printing page"),
+        DeviceStatus::Completed => emit_info("Page complete"),
+        _ => {}
+    }
+}
+
+/// Threshold and pack one raster scanline into the `[u8; 16]` line format
+/// `print_raw` expects, a set bit is a printed (black) dot
+fn pack_line(scanline: &[u8], header: &PageHeader) -> [u8; LINE_BYTES] {
+    let mut out = [0u8; LINE_BYTES];
+
+    let width = (header.width as usize).min(LINE_DOTS);
+
+    // 1bpp is what CUPS raster filters emit for monochrome thermal/label
+    // media, each scanline byte already packs 8 pixels rather than holding
+    // one greyscale sample per byte, so it needs its own unpacking path
+    if header.bits_per_pixel == 1 {
+        for x in 0..width {
+            let byte = scanline.get(x / 8).copied().unwrap_or(0x00);
+
+            if byte & (0x80 >> (x % 8)) != 0 {
+                out[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+
+        return out;
+    }
+
+    let bytes_per_pixel = ((header.bits_per_pixel / 8) as usize).max(1);
+
+    for x in 0..width {
+        let value = scanline.get(x * bytes_per_pixel).copied().unwrap_or(0xff);
+
+        // CUPS raster is 0 = black / 255 = white for greyscale planes
+        if value < 0x80 {
+            out[x / 8] |= 0x80 >> (x % 8);
+        }
+    }
+
+    out
+}
+
+/// Derive the `PrintInfo` media width from the page header
+// TODO: this assumes the page header's resolution is the printer's native
+// 180dpi, a fuller implementation would read HWResolution out of the header
+fn build_print_info(header: &PageHeader) -> PrintInfo {
+    let mut info = PrintInfo::default();
+    info.media_mm = header.width / (180 / 25);
+    info
+}
+
+fn read_page(input: &mut dyn Read) -> io::Result<Option<(PageHeader, Vec<[u8; LINE_BYTES]>)>> {
+    let mut header_buff = [0u8; PAGE_HEADER_LEN];
+
+    match input.read_exact(&mut header_buff) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let header = PageHeader::parse(&header_buff);
+
+    let mut lines = Vec::with_capacity(header.height as usize);
+    let mut scanline = vec![0u8; header.bytes_per_line as usize];
+
+    for _ in 0..header.height {
+        input.read_exact(&mut scanline)?;
+        lines.push(pack_line(&scanline, &header));
+    }
+
+    Ok(Some((header, lines)))
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+
+    // No-arg invocation: CUPS is asking the backend to report discovered devices
+    if args.len() == 1 {
+        let context = rusb::Context::new().expect("failed to create USB context");
+
+        match PTouch::list(&context) {
+            Ok(devices) => {
+                for d in devices {
+                    println!(
+                        "direct ptouch://{} \"{}\" \"{}\" \"\"",
+                        d.info.serial, d.info.manufacturer, d.info.product
+                    );
+                }
+            }
+            Err(e) => eprintln!("ERROR: failed to enumerate devices: {:?}", e),
+        }
+
+        return;
+    }
+
+    if args.len() < 6 {
+        eprintln!(
+            "ERROR: usage: {} job-id user title copies options [file]",
+            args[0]
+        );
+        exit(1);
+    }
+
+    let copies: usize = args[4].parse().unwrap_or(1);
+
+    let mut input: Box<dyn Read> = match args.get(6) {
+        Some(path) => Box::new(std::fs::File::open(path).expect("failed to open print file")),
+        None => Box::new(io::stdin()),
+    };
+
+    let mut sync = [0u8; 4];
+    if let Err(e) = io::Read::read_exact(&mut input, &mut sync) {
+        eprintln!("ERROR: failed to read raster sync word: {:?}", e);
+        exit(1);
+    }
+    if sync != SYNC_V2 {
+        eprintln!("ERROR: unsupported CUPS raster version {:?}", sync);
+        exit(1);
+    }
+
+    emit_state("printer-idle");
+
+    // Resolve a device to print to: CUPS passes the device-uri emitted by
+    // the no-arg discovery above (`ptouch://{serial}`) in DEVICE_URI, match
+    // that against the discovered serials so multi-printer setups land on
+    // the requested device rather than always the first one found
+    let context = rusb::Context::new().expect("failed to create USB context");
+    let devices = match PTouch::list(&context) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("ERROR: failed to enumerate devices: {:?}", e);
+            exit(1);
+        }
+    };
+
+    let wanted_serial = env::var("DEVICE_URI")
+        .ok()
+        .and_then(|uri| uri.strip_prefix("ptouch://").map(str::to_string));
+
+    let selected = match &wanted_serial {
+        Some(serial) => devices.iter().position(|d| &d.info.serial == serial),
+        None => devices.iter().position(|d| d.kind.is_some()),
+    }
+    .unwrap_or_else(|| {
+        eprintln!("ERROR: no supported Brother P-touch devices found");
+        exit(1);
+    });
+
+    let target = devices[selected].kind.unwrap_or_else(|| {
+        eprintln!(
+            "ERROR: device {} is not a supported P-touch model",
+            devices[selected].info.serial
+        );
+        exit(1);
+    });
+
+    // `PTouch::new_with_context` indexes into devices filtered by kind
+    // alone, so translate the absolute position above into a same-kind index
+    let index = devices[..selected]
+        .iter()
+        .filter(|d| d.kind == Some(target))
+        .count();
+
+    let filter = Filter {
+        device: target,
+        index,
+    };
+
+    let mut ptouch = match PTouch::new_with_context(&filter, &context) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("ERROR: failed to open printer: {:?}", e);
+            exit(1);
+        }
+    };
+
+    loop {
+        let (header, lines) = match read_page(&mut input) {
+            Ok(Some(page)) => page,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("ERROR: failed to read raster page: {:?}", e);
+                exit(1);
+            }
+        };
+
+        let info = build_print_info(&header);
+
+        for _copy in 0..copies.max(1) {
+            if let Err(e) = ptouch.print_raw_with_status(lines.clone(), &info, emit_status) {
+                eprintln!("ERROR: print failed: {:?}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if let Ok(status) = ptouch.status() {
+        emit_status(&status);
+    }
+
+    emit_state("-printer-idle");
+}
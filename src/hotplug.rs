@@ -0,0 +1,152 @@
+//! USB hotplug support, so callers can react to label makers being plugged
+//! in or removed instead of polling [`PTouch::new`](crate::PTouch::new).
+//!
+//! This is a thin wrapper around libusb's hotplug callback support as
+//! exposed through `rusb`, filtered to [`BROTHER_VID`](crate::BROTHER_VID).
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::debug;
+use rusb::{Context, Device, DeviceDescriptor, Hotplug, HotplugBuilder, Registration, UsbContext};
+
+use crate::device::PTouchDevice;
+use crate::{Error, PTouch, BROTHER_VID};
+
+/// Interval to poll `Context::handle_events` on the background watch thread
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Event delivered to a [`PTouch::watch`] callback
+#[derive(Debug)]
+pub enum HotplugEvent {
+    /// A matching device has been plugged in
+    Arrived(Device<Context>, DeviceDescriptor, Option<PTouchDevice>),
+    /// A previously discovered device has been unplugged
+    Left(Device<Context>, DeviceDescriptor, Option<PTouchDevice>),
+}
+
+struct Handler<F> {
+    filter: Option<PTouchDevice>,
+    callback: F,
+}
+
+impl<F> Handler<F>
+where
+    F: FnMut(HotplugEvent) + Send,
+{
+    fn dispatch(&mut self, device: Device<Context>, arrived: bool) {
+        let desc = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(e) => {
+                debug!("Could not fetch descriptor for hotplug device: {:?}", e);
+                return;
+            }
+        };
+
+        let kind = PTouchDevice::try_from(desc.product_id()).ok();
+
+        if let Some(filter) = &self.filter {
+            if kind.as_ref() != Some(filter) {
+                return;
+            }
+        }
+
+        let event = match arrived {
+            true => HotplugEvent::Arrived(device, desc, kind),
+            false => HotplugEvent::Left(device, desc, kind),
+        };
+
+        (self.callback)(event);
+    }
+}
+
+impl<F> Hotplug<Context> for Handler<F>
+where
+    F: FnMut(HotplugEvent) + Send,
+{
+    fn device_arrived(&mut self, device: Device<Context>) {
+        self.dispatch(device, true);
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        self.dispatch(device, false);
+    }
+}
+
+/// Handle to an active hotplug watch
+///
+/// Dropping this stops the background event thread and deregisters the
+/// hotplug callback.
+pub struct Watch {
+    running: Arc<AtomicBool>,
+    // Held for its lifetime only, the registration is torn down on drop
+    _registration: Registration<Context>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl PTouch {
+    /// Watch for P-touch devices being attached or removed
+    ///
+    /// `filter` restricts events to a specific [`PTouchDevice`] kind, or
+    /// `None` to match any device with the Brother vendor ID (the event's
+    /// kind is then `None` where the product ID is not recognised).
+    /// Devices already attached when the watch is registered are reported
+    /// as [`HotplugEvent::Arrived`] during registration, on the calling
+    /// thread; later events invoke `callback` from a background thread
+    /// until the returned [`Watch`] is dropped.
+    pub fn watch<F>(filter: Option<PTouchDevice>, callback: F) -> Result<Watch, Error>
+    where
+        F: FnMut(HotplugEvent) + Send + 'static,
+    {
+        Self::watch_with_context(filter, callback, &crate::CONTEXT)
+    }
+
+    /// As [`PTouch::watch`] but using a caller-provided `rusb::Context`
+    pub fn watch_with_context<F>(
+        filter: Option<PTouchDevice>,
+        callback: F,
+        context: &Context,
+    ) -> Result<Watch, Error>
+    where
+        F: FnMut(HotplugEvent) + Send + 'static,
+    {
+        if !rusb::has_hotplug() {
+            return Err(Error::HotplugUnsupported);
+        }
+
+        let registration = HotplugBuilder::new()
+            .vendor_id(BROTHER_VID)
+            .enumerate(true)
+            .register(context, Box::new(Handler { filter, callback }))?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let bg_running = running.clone();
+        let bg_context = context.clone();
+
+        let handle = std::thread::spawn(move || {
+            while bg_running.load(Ordering::SeqCst) {
+                if let Err(e) = bg_context.handle_events(Some(POLL_INTERVAL)) {
+                    debug!("Error polling hotplug events: {:?}", e);
+                }
+            }
+        });
+
+        Ok(Watch {
+            running,
+            _registration: registration,
+            handle: Some(handle),
+        })
+    }
+}
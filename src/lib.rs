@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::time::Duration;
 
 use commands::Commands;
@@ -16,6 +17,8 @@ pub mod tiff;
 
 pub mod render;
 
+pub mod hotplug;
+
 pub struct PTouch {
     _device: Device<Context>,
     handle: DeviceHandle<Context>,
@@ -25,6 +28,13 @@ pub struct PTouch {
 
     cmd_ep: u8,
     stat_ep: u8,
+
+    interface: u8,
+    interface_alt: u8,
+
+    // Set while `recover()` is in progress, guards against recursing back
+    // into `recover()` via the `read`/`write`/`status` calls it makes
+    recovering: bool,
 }
 
 pub const BROTHER_VID: u16 = 0x04F9;
@@ -45,7 +55,7 @@ pub struct Filter {
 
 // Lazy initialised libusb context
 lazy_static::lazy_static! {
-    static ref CONTEXT: Context = {
+    pub(crate) static ref CONTEXT: Context = {
         Context::new().unwrap()
     };
 }
@@ -72,6 +82,9 @@ pub enum Error {
 
     #[error("PTouch Error ({:?} {:?})", 0, 1)]
     PTouch(Error1, Error2),
+
+    #[error("libusb hotplug support not available")]
+    HotplugUnsupported,
 }
 
 impl From<rusb::Error> for Error {
@@ -87,6 +100,85 @@ pub struct Info {
     pub serial: String,
 }
 
+/// Lightweight descriptor for a discovered (but not yet opened) device
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    /// Resolved device kind, `None` if the product ID is not recognised
+    pub kind: Option<device::PTouchDevice>,
+    pub bus: u8,
+    pub address: u8,
+    pub info: Info,
+}
+
+// USB Printer-Class (interface class 7) control requests, see the
+// "USB Class Definition for Printing Devices" spec.
+const PRINTER_CLASS_GET_DEVICE_ID: u8 = 0;
+const PRINTER_CLASS_GET_PORT_STATUS: u8 = 1;
+const PRINTER_CLASS_SOFT_RESET: u8 = 2;
+
+/// IEEE-1284 Device ID, as returned by the printer-class GET_DEVICE_ID request
+///
+/// This is a `KEY:value;` encoded string, the fields of interest here
+/// (manufacturer, model, supported command sets and a description) are
+/// pulled out for convenience, the remainder is retained in `other`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceId {
+    pub manufacturer: String,
+    pub model: String,
+    pub command_sets: Vec<String>,
+    pub description: String,
+    pub other: Vec<(String, String)>,
+}
+
+impl DeviceId {
+    /// Parse an IEEE-1284 `KEY:value;KEY:value;...` string into a [`DeviceId`]
+    fn parse(s: &str) -> Self {
+        let mut d = DeviceId::default();
+
+        for field in s.split(';') {
+            let field = field.trim();
+            if field.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match field.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+
+            match key.trim() {
+                "MFG" | "MANUFACTURER" => d.manufacturer = value.trim().to_string(),
+                "MDL" | "MODEL" => d.model = value.trim().to_string(),
+                "CMD" | "COMMAND SET" => {
+                    d.command_sets = value.split(',').map(|v| v.trim().to_string()).collect()
+                }
+                "DES" | "DESCRIPTION" => d.description = value.trim().to_string(),
+                k => d.other.push((k.to_string(), value.trim().to_string())),
+            }
+        }
+
+        d
+    }
+}
+
+/// Port status bits, as returned by the printer-class GET_PORT_STATUS request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortStatus {
+    pub paper_empty: bool,
+    pub selected: bool,
+    pub not_error: bool,
+}
+
+impl From<u8> for PortStatus {
+    fn from(b: u8) -> Self {
+        Self {
+            paper_empty: b & 0b0010_0000 != 0,
+            selected: b & 0b0001_0000 != 0,
+            not_error: b & 0b0000_1000 != 0,
+        }
+    }
+}
+
 impl PTouch {
     /// Create a new PTouch driver with the provided USB options
     pub fn new(o: &Filter) -> Result<Self, Error> {
@@ -171,16 +263,26 @@ impl PTouch {
         // EP2 is a bulk OUT (PC -> printer) endpoint for print commands
         // TODO: is this worth it, could we just, hard-code the endpoints?
         let (mut cmd_ep, mut stat_ep) = (None, None);
+        let mut interface_alt = 0u8;
 
         for interface_desc in interface.descriptors() {
+            let (mut desc_cmd_ep, mut desc_stat_ep) = (None, None);
+
             for endpoint_desc in interface_desc.endpoint_descriptors() {
                 // Find the relevant endpoints
                 match (endpoint_desc.transfer_type(), endpoint_desc.direction()) {
-                    (TransferType::Bulk, Direction::In) => stat_ep = Some(endpoint_desc.address()),
-                    (TransferType::Bulk, Direction::Out) => cmd_ep = Some(endpoint_desc.address()),
+                    (TransferType::Bulk, Direction::In) => desc_stat_ep = Some(endpoint_desc.address()),
+                    (TransferType::Bulk, Direction::Out) => desc_cmd_ep = Some(endpoint_desc.address()),
                     (_, _) => continue,
                 }
             }
+
+            if let (Some(cmd), Some(stat)) = (desc_cmd_ep, desc_stat_ep) {
+                cmd_ep = Some(cmd);
+                stat_ep = Some(stat);
+                interface_alt = interface_desc.setting_number();
+                break;
+            }
         }
 
         let (cmd_ep, stat_ep) = match (cmd_ep, stat_ep) {
@@ -219,7 +321,10 @@ impl PTouch {
             descriptor,
             cmd_ep,
             stat_ep,
+            interface: interface.number(),
+            interface_alt,
             timeout: DEFAULT_TIMEOUT,
+            recovering: false,
         };
 
 
@@ -230,6 +335,70 @@ impl PTouch {
         Ok(s)
     }
 
+    /// List all connected and supported devices
+    ///
+    /// Unlike [`PTouch::new`] this does not require the caller to know the
+    /// device kind (or index) ahead of time, matching instead on
+    /// [`BROTHER_VID`] alone. This is intended for UI device pickers and
+    /// multi-printer setups, each device is briefly opened to fetch its
+    /// manufacturer/product/serial strings.
+    pub fn list(context: &Context) -> Result<Vec<DeviceInfo>, Error> {
+        let devices = context.devices()?;
+        let mut out = vec![];
+
+        for device in devices.iter() {
+            let descriptor = match device.device_descriptor() {
+                Ok(d) => d,
+                Err(e) => {
+                    debug!("Could not fetch descriptor for device {:?}: {:?}", device, e);
+                    continue;
+                }
+            };
+
+            if descriptor.vendor_id() != BROTHER_VID {
+                continue;
+            }
+
+            let kind = device::PTouchDevice::try_from(descriptor.product_id()).ok();
+
+            let info = match Self::read_info(&device, &descriptor) {
+                Ok(i) => i,
+                Err(e) => {
+                    debug!("Failed to read device info for {:?}: {:?}", device, e);
+                    continue;
+                }
+            };
+
+            out.push(DeviceInfo {
+                kind,
+                bus: device.bus_number(),
+                address: device.address(),
+                info,
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Briefly open a device to fetch its manufacturer/product/serial strings
+    fn read_info(device: &Device<Context>, descriptor: &DeviceDescriptor) -> Result<Info, Error> {
+        let timeout = Duration::from_millis(200);
+
+        let handle = device.open()?;
+
+        let languages = handle.read_languages(timeout)?;
+        if languages.is_empty() {
+            return Err(Error::NoLanguages);
+        }
+        let language = languages[0];
+
+        Ok(Info {
+            manufacturer: handle.read_manufacturer_string(language, descriptor, timeout)?,
+            product: handle.read_product_string(language, descriptor, timeout)?,
+            serial: handle.read_serial_number_string(language, descriptor, timeout)?,
+        })
+    }
+
     /// Fetch device information
     pub fn info(&mut self) -> Result<Info, Error> {
         let timeout = Duration::from_millis(200);
@@ -265,6 +434,87 @@ impl PTouch {
         })
     }
 
+    /// Fetch the IEEE-1284 device ID via the USB Printer-Class GET_DEVICE_ID request
+    pub fn device_id(&mut self) -> Result<DeviceId, Error> {
+        let config = self.handle.active_configuration()?;
+
+        let mut buff = [0u8; 256];
+
+        let n = self.handle.read_control(
+            rusb::request_type(
+                rusb::Direction::In,
+                rusb::RequestType::Class,
+                rusb::Recipient::Interface,
+            ),
+            PRINTER_CLASS_GET_DEVICE_ID,
+            config as u16,
+            ((self.interface as u16) << 8) | self.interface_alt as u16,
+            &mut buff,
+            self.timeout,
+        )?;
+
+        if n < 2 {
+            return Err(Error::Timeout);
+        }
+
+        // First two bytes are a big-endian length, including themselves
+        let len = u16::from_be_bytes([buff[0], buff[1]]) as usize;
+        let len = len.min(n).saturating_sub(2);
+
+        let s = String::from_utf8_lossy(&buff[2..2 + len]);
+
+        debug!("Device ID: {}", s);
+
+        Ok(DeviceId::parse(&s))
+    }
+
+    /// Fetch the port status byte via the USB Printer-Class GET_PORT_STATUS request
+    pub fn port_status(&mut self) -> Result<PortStatus, Error> {
+        let mut buff = [0u8; 1];
+
+        let n = self.handle.read_control(
+            rusb::request_type(
+                rusb::Direction::In,
+                rusb::RequestType::Class,
+                rusb::Recipient::Interface,
+            ),
+            PRINTER_CLASS_GET_PORT_STATUS,
+            0,
+            self.interface as u16,
+            &mut buff,
+            self.timeout,
+        )?;
+
+        if n != 1 {
+            return Err(Error::Timeout);
+        }
+
+        Ok(PortStatus::from(buff[0]))
+    }
+
+    /// Issue a USB Printer-Class SOFT_RESET request
+    ///
+    /// This resets the printer-class interface state machine, it does not
+    /// reset the underlying USB device. [`PTouch::recover`] does not reset
+    /// the USB device either, it clears halted bulk endpoints and
+    /// re-initialises the printer.
+    pub fn soft_reset(&mut self) -> Result<(), Error> {
+        self.handle.write_control(
+            rusb::request_type(
+                rusb::Direction::Out,
+                rusb::RequestType::Class,
+                rusb::Recipient::Interface,
+            ),
+            PRINTER_CLASS_SOFT_RESET,
+            0,
+            self.interface as u16,
+            &[],
+            self.timeout,
+        )?;
+
+        Ok(())
+    }
+
     pub fn status(&mut self) -> Result<Status, Error> {
         // Issue status request
         self.status_req()?;
@@ -281,6 +531,22 @@ impl PTouch {
     }
 
     pub fn print_raw(&mut self, data: Vec<[u8; 16]>, info: &PrintInfo) -> Result<(), Error> {
+        self.print_raw_with_status(data, info, |_| {})
+    }
+
+    /// As [`PTouch::print_raw`] but invoking `on_status` with every status
+    /// polled while printing, so callers (e.g. the CUPS backend) can
+    /// surface progress/errors as they happen rather than only once the
+    /// whole job has finished
+    pub fn print_raw_with_status<F>(
+        &mut self,
+        data: Vec<[u8; 16]>,
+        info: &PrintInfo,
+        mut on_status: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&Status),
+    {
         // TODO: should we check things are compatible here?
 
 
@@ -365,6 +631,8 @@ impl PTouch {
         // Poll on print completion
         loop {
             if let Ok(s) = self.read_status(self.timeout) {
+                on_status(&s);
+
                 if !s.error1.is_empty() || !s.error2.is_empty() {
                     debug!("Print error: {:?} {:?}", s.error1, s.error2);
                     return Err(Error::PTouch(s.error1, s.error2));
@@ -381,7 +649,8 @@ impl PTouch {
             }
 
             if i > 10 {
-                debug!("Print timeout");
+                debug!("Print timeout, attempting recovery");
+                self.recover()?;
                 return Err(Error::Timeout);
             }
 
@@ -398,8 +667,16 @@ impl PTouch {
     fn read(&mut self, timeout: Duration) -> Result<[u8; 32], Error> {
         let mut buff = [0u8; 32];
 
-        // Execute read
-        let n = self.handle.read_bulk(self.stat_ep, &mut buff, timeout)?;
+        // Execute read, recovering from a stalled endpoint
+        let n = match self.handle.read_bulk(self.stat_ep, &mut buff, timeout) {
+            Ok(n) => n,
+            Err(rusb::Error::Pipe) => {
+                warn!("Status endpoint stalled, attempting recovery");
+                self.recover()?;
+                return Err(Error::Timeout);
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         if n != 32 {
             return Err(Error::Timeout)
@@ -414,8 +691,16 @@ impl PTouch {
     fn write(&mut self, data: &[u8], timeout: Duration) -> Result<(), Error> {
         warn!("WRITE: {:02x?}", data);
 
-        // Execute write
-        let n = self.handle.write_bulk(self.cmd_ep, &data, timeout)?;
+        // Execute write, recovering from a stalled endpoint
+        let n = match self.handle.write_bulk(self.cmd_ep, &data, timeout) {
+            Ok(n) => n,
+            Err(rusb::Error::Pipe) => {
+                warn!("Command endpoint stalled, attempting recovery");
+                self.recover()?;
+                return Err(Error::Timeout);
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         // Check write length for timeouts
         if n != data.len() {
@@ -424,4 +709,54 @@ impl PTouch {
 
         Ok(())
     }
+
+    /// Recover from a stalled bulk endpoint or a wedged device
+    ///
+    /// This clears the halt condition on both bulk endpoints, then runs the
+    /// same invalidate/init abort sequence used during connection, and
+    /// confirms the device has returned to a ready phase before handing
+    /// control back to the caller.
+    pub fn recover(&mut self) -> Result<(), Error> {
+        // `invalidate`/`init`/`status` below route back through `write`/`read`,
+        // which call `recover` again on a stall - bail out immediately rather
+        // than recursing if a device that's genuinely wedged (not just
+        // transiently stalled) fails to come back up
+        if self.recovering {
+            debug!("Already attempting recovery, aborting to avoid recursing");
+            return Err(Error::Timeout);
+        }
+
+        self.recovering = true;
+        let result = self.recover_inner();
+        self.recovering = false;
+
+        result
+    }
+
+    fn recover_inner(&mut self) -> Result<(), Error> {
+        warn!("Attempting to recover wedged device");
+
+        // Clear halt condition on both bulk endpoints
+        if let Err(e) = self.handle.clear_halt(self.stat_ep) {
+            debug!("Error clearing halt on status endpoint: {:?}", e);
+        }
+        if let Err(e) = self.handle.clear_halt(self.cmd_ep) {
+            debug!("Error clearing halt on command endpoint: {:?}", e);
+        }
+
+        // Abort any in-progress transfer and reinitialise the device
+        self.invalidate()?;
+        self.init()?;
+
+        // Confirm the device has returned to a ready phase
+        let s = self.status()?;
+        if !s.error1.is_empty() || !s.error2.is_empty() {
+            debug!("Device not ready after recovery: {:?}", s);
+            return Err(Error::PTouch(s.error1, s.error2));
+        }
+
+        debug!("Recovery successful");
+
+        Ok(())
+    }
 }